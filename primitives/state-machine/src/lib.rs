@@ -118,7 +118,7 @@ impl sp_std::fmt::Display for DefaultError {
 pub use crate::overlayed_changes::{
 	OverlayedChanges, StorageKey, StorageValue,
 	StorageCollection, ChildStorageCollection,
-	StorageChanges, StorageTransactionCache,
+	StorageChanges, StorageTransactionCache, NoOpenTransaction,
 };
 pub use crate::backend::Backend;
 pub use crate::trie_backend_essence::{TrieBackendStorage, Storage};
@@ -17,12 +17,16 @@
 
 //! Test implementation for Externalities.
 
-use std::any::{Any, TypeId};
+use std::{
+	any::{Any, TypeId},
+	collections::{BTreeMap, BTreeSet, HashMap},
+};
 use codec::Decode;
 use hash_db::Hasher;
 use crate::{
 	backend::Backend, OverlayedChanges, StorageTransactionCache, ext::Ext, InMemoryBackend,
-	StorageKey, StorageValue,
+	StorageKey, StorageValue, StorageCollection, StorageProof, ProvingBackend, NoOpenTransaction,
+	overlayed_changes::OverlayedValue,
 	changes_trie::{
 		Configuration as ChangesTrieConfiguration,
 		InMemoryStorage as ChangesTrieInMemoryStorage,
@@ -37,7 +41,7 @@ use sp_core::{
 	},
 	storage::{
 		well_known_keys::{CHANGES_TRIE_CONFIG, CODE, HEAP_PAGES, is_child_storage_key},
-		Storage,
+		ChildInfo, Storage,
 	},
 	traits::TaskExecutorExt,
 	testing::TaskExecutor,
@@ -68,20 +72,43 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 {
 	/// Get externalities implementation.
 	pub fn ext(&mut self) -> Ext<H, N, InMemoryBackend<H>> {
-		Ext::new(
+		Self::build_ext(
 			&mut self.overlay,
 			&mut self.offchain_overlay,
 			&mut self.storage_transaction_cache,
 			&self.backend,
-			match self.changes_trie_config.clone() {
+			&self.changes_trie_config,
+			&self.changes_trie_storage,
+			&mut self.extensions,
+		)
+	}
+
+	/// Build externalities reading from `backend` on top of the given state of an instance.
+	///
+	/// Takes the fields separately, so that `backend` may borrow from the same instance.
+	fn build_ext<'a, B: Backend<H>>(
+		overlay: &'a mut OverlayedChanges,
+		offchain_overlay: &'a mut OffchainOverlayedChanges,
+		storage_transaction_cache: &'a mut StorageTransactionCache<B::Transaction, H, N>,
+		backend: &'a B,
+		changes_trie_config: &Option<ChangesTrieConfiguration>,
+		changes_trie_storage: &'a ChangesTrieInMemoryStorage<H, N>,
+		extensions: &'a mut Extensions,
+	) -> Ext<'a, H, N, B> {
+		Ext::new(
+			overlay,
+			offchain_overlay,
+			storage_transaction_cache,
+			backend,
+			match changes_trie_config.clone() {
 				Some(config) => Some(ChangesTrieState {
 					config,
 					zero: 0.into(),
-					storage: &self.changes_trie_storage,
+					storage: changes_trie_storage,
 				}),
 				None => None,
 			},
-			Some(&mut self.extensions),
+			Some(extensions),
 		)
 	}
 
@@ -171,6 +198,43 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		self.backend.update(transaction)
 	}
 
+	/// Open a new checkpoint on top of the current storage overlay.
+	///
+	/// All storage overlay changes made afterwards can be discarded with `rollback_to` or
+	/// kept with `commit`. Checkpoints nest; the returned value is the nesting depth of the
+	/// one just opened, not a unique handle: once a checkpoint is closed, the next one opened
+	/// at the same depth returns the same value.
+	///
+	/// Offchain storage writes and values added with `insert` bypass checkpoints and are
+	/// kept on rollback.
+	pub fn checkpoint(&mut self) -> usize {
+		self.overlay.start_transaction();
+		self.overlay.transaction_depth()
+	}
+
+	/// Discard all storage overlay changes made since the checkpoint at nesting depth
+	/// `checkpoint` was opened.
+	///
+	/// This also closes any checkpoint nested inside it. Returns an error if no checkpoint
+	/// is open at that depth.
+	pub fn rollback_to(&mut self, checkpoint: usize) -> Result<(), NoOpenTransaction> {
+		if checkpoint == 0 || checkpoint > self.overlay.transaction_depth() {
+			return Err(NoOpenTransaction)
+		}
+		self.storage_transaction_cache.reset();
+		while self.overlay.transaction_depth() >= checkpoint {
+			self.overlay.rollback_transaction()?;
+		}
+		Ok(())
+	}
+
+	/// Close the most recently opened checkpoint, keeping its changes.
+	///
+	/// Returns an error if there is no open checkpoint.
+	pub fn commit(&mut self) -> Result<(), NoOpenTransaction> {
+		self.overlay.commit_transaction()
+	}
+
 	/// Execute the given closure while `self` is set as externalities.
 	///
 	/// Returns the result of the given closure.
@@ -178,6 +242,134 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		let mut ext = self.ext();
 		sp_externalities::set_and_run_with_externalities(&mut ext, execute)
 	}
+
+	/// Execute the given closure while `self` is set as externalities.
+	///
+	/// Returns the result of the given closure together with the storage changes it made,
+	/// in the same shape `commit_all` applies them: the top-level changes first, followed
+	/// by one entry per changed child trie. Keys are ordered and a `None` value denotes a
+	/// deleted key. Writes that leave a value as it was are not reported, while keys the
+	/// call drops from the overlay, e.g. by rolling back a checkpoint, are reported with
+	/// their backend value.
+	pub fn execute_with_changes<R>(
+		&mut self,
+		execute: impl FnOnce() -> R,
+	) -> (R, Vec<(Option<ChildInfo>, StorageCollection)>) {
+		let top_before = overlay_values(self.overlay.changes());
+		let children_before = child_overlay_values(&self.overlay);
+
+		let result = self.execute_with(execute);
+
+		let backend = &self.backend;
+		let top = changed_values(
+			top_before,
+			overlay_values(self.overlay.changes()),
+			|key| backend.storage(key).expect(BACKEND_NOT_ALLOWED_TO_FAIL),
+		);
+
+		// Child changesets may be dropped from the overlay by a rollback, so walk the
+		// children present either before or after the call.
+		let mut children_after = child_overlay_values(&self.overlay);
+		let mut children = BTreeMap::new();
+		for (storage_key, (info, before)) in children_before {
+			let after = children_after.remove(&storage_key)
+				.map_or_else(Default::default, |(_, after)| after);
+			children.insert(storage_key, (info, before, after));
+		}
+		for (storage_key, (info, after)) in children_after {
+			children.insert(storage_key, (info, Default::default(), after));
+		}
+
+		let mut changes = vec![(None, top)];
+		changes.extend(children.into_iter().filter_map(|(_, (info, before, after))| {
+			let child_changes = changed_values(
+				before,
+				after,
+				|key| backend.child_storage(&info, key).expect(BACKEND_NOT_ALLOWED_TO_FAIL),
+			);
+			if child_changes.is_empty() {
+				None
+			} else {
+				Some((Some(info), child_changes))
+			}
+		}));
+
+		(result, changes)
+	}
+
+	/// Execute the given closure while `self`, wrapped in a proving backend, is set as
+	/// externalities.
+	///
+	/// Returns the result of the given closure together with a proof of all backend
+	/// storage it read.
+	pub fn execute_and_prove<R>(&mut self, execute: impl FnOnce() -> R) -> (R, StorageProof) {
+		// A cached storage root would be returned without reading the backend, leaving the
+		// nodes needed to recompute it out of the proof.
+		self.storage_transaction_cache.reset();
+
+		let proving_backend = ProvingBackend::new(&self.backend);
+		let mut proving_ext = Self::build_ext(
+			&mut self.overlay,
+			&mut self.offchain_overlay,
+			&mut self.storage_transaction_cache,
+			&proving_backend,
+			&self.changes_trie_config,
+			&self.changes_trie_storage,
+			&mut self.extensions,
+		);
+
+		let result = sp_externalities::set_and_run_with_externalities(&mut proving_ext, execute);
+		let proof = proving_backend.extract_proof();
+
+		(result, proof)
+	}
+}
+
+const BACKEND_NOT_ALLOWED_TO_FAIL: &str = "The in-memory backend does not fail; qed";
+
+/// Values of storage keys as seen by the overlay, `None` denoting a deleted key.
+type OverlayValues = HashMap<StorageKey, Option<StorageValue>>;
+
+/// Collect the current value of every key in the given overlay changes.
+fn overlay_values<'a>(
+	changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>,
+) -> OverlayValues {
+	changes.map(|(k, v)| (k.clone(), v.value().cloned())).collect()
+}
+
+/// Collect the current value of every key in the child overlay changes, by child storage key.
+fn child_overlay_values(
+	overlay: &OverlayedChanges,
+) -> BTreeMap<StorageKey, (ChildInfo, OverlayValues)> {
+	overlay.children()
+		.map(|(changes, info)| {
+			(info.storage_key().to_vec(), (info.clone(), overlay_values(changes)))
+		})
+		.collect()
+}
+
+/// Collect the keys whose value differs between `before` and `after`, ordered by key.
+///
+/// A key missing from either side has its value in the backend there.
+fn changed_values(
+	before: OverlayValues,
+	after: OverlayValues,
+	backend_value: impl Fn(&[u8]) -> Option<StorageValue>,
+) -> StorageCollection {
+	let value = |values: &OverlayValues, key: &StorageKey| {
+		values.get(key).cloned().unwrap_or_else(|| backend_value(&key[..]))
+	};
+	let keys: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+	keys.into_iter()
+		.filter_map(|key| {
+			let new = value(&after, key);
+			if value(&before, key) != new {
+				Some((key.clone(), new))
+			} else {
+				None
+			}
+		})
+		.collect()
 }
 
 impl<H: Hasher, N: ChangesTrieBlockNumber> std::fmt::Debug for TestExternalities<H, N>
@@ -243,7 +435,10 @@ impl<H, N> sp_externalities::ExtensionStore for TestExternalities<H, N> where
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use sp_core::{H256, traits::Externalities};
+	use sp_core::{
+		H256, map, traits::Externalities, storage::StorageChild,
+		offchain::OffchainStorage,
+	};
 	use sp_runtime::traits::BlakeTwo256;
 	use hex_literal::hex;
 
@@ -269,6 +464,193 @@ mod tests {
 		assert_eq!(&ext.storage(CODE).unwrap(), &code);
 	}
 
+	#[test]
+	fn execute_with_changes_returns_only_new_changes() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.ext().set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+
+		let (result, changes) = ext.execute_with_changes(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+				ext.set_storage(b"dog".to_vec(), b"cat".to_vec());
+				ext.clear_storage(b"doe");
+				ext.set_storage(b"dogglesworth".to_vec(), b"cat".to_vec());
+			}).is_some()
+		});
+
+		assert!(result);
+		assert_eq!(changes, vec![(None, vec![
+			(b"doe".to_vec(), None),
+			(b"dog".to_vec(), Some(b"cat".to_vec())),
+			(b"dogglesworth".to_vec(), Some(b"cat".to_vec())),
+		])]);
+	}
+
+	#[test]
+	fn execute_with_changes_compares_against_backend() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+
+		let ((), changes) = ext.execute_with_changes(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+				ext.set_storage(b"dog".to_vec(), b"puppy".to_vec());
+			}).unwrap()
+		});
+
+		assert_eq!(changes, vec![(None, vec![(b"dog".to_vec(), Some(b"puppy".to_vec()))])]);
+	}
+
+	#[test]
+	fn execute_with_changes_reports_child_changes() {
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::new(Storage {
+			top: Default::default(),
+			children_default: map![
+				child_info.prefixed_storage_key().into_inner() => StorageChild {
+					data: map![	b"doe".to_vec() => b"reindeer".to_vec()	],
+					child_info: child_info.clone(),
+				}
+			]
+		});
+
+		let ((), changes) = ext.execute_with_changes(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.set_child_storage(&child_info, b"doe".to_vec(), b"reindeer".to_vec());
+				ext.set_child_storage(&child_info, b"dog".to_vec(), b"puppy".to_vec());
+				ext.clear_child_storage(&child_info, b"doe");
+			}).unwrap()
+		});
+
+		assert_eq!(changes, vec![
+			(None, vec![]),
+			(Some(child_info.clone()), vec![
+				(b"doe".to_vec(), None),
+				(b"dog".to_vec(), Some(b"puppy".to_vec())),
+			]),
+		]);
+	}
+
+	#[test]
+	fn execute_with_changes_reports_rolled_back_keys() {
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+
+		ext.checkpoint();
+		ext.ext().set_storage(b"doe".to_vec(), b"deer".to_vec());
+		ext.ext().set_child_storage(&child_info, b"dog".to_vec(), b"puppy".to_vec());
+
+		let ((), changes) = ext.execute_with_changes(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.storage_start_transaction();
+				ext.set_storage(b"dogglesworth".to_vec(), b"cat".to_vec());
+				ext.storage_rollback_transaction().unwrap();
+				ext.storage_rollback_transaction().unwrap();
+			}).unwrap()
+		});
+
+		assert_eq!(changes, vec![
+			(None, vec![(b"doe".to_vec(), Some(b"reindeer".to_vec()))]),
+			(Some(child_info), vec![(b"dog".to_vec(), None)]),
+		]);
+	}
+
+	#[test]
+	fn execute_and_prove_records_read_storage() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.insert(b"dog".to_vec(), b"puppy".to_vec());
+		let root = ext.backend.root().clone();
+
+		let (value, proof) = ext.execute_and_prove(|| {
+			sp_externalities::with_externalities(|ext| ext.storage(b"doe")).unwrap()
+		});
+		assert_eq!(value, Some(b"reindeer".to_vec()));
+
+		let proof_check = crate::create_proof_check_backend::<BlakeTwo256>(root, proof).unwrap();
+		assert_eq!(proof_check.storage(b"doe").unwrap(), Some(b"reindeer".to_vec()));
+	}
+
+	#[test]
+	fn execute_and_prove_records_cached_storage_root() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.insert(b"dog".to_vec(), b"puppy".to_vec());
+		let root = ext.backend.root().clone();
+		let storage_root = || {
+			sp_externalities::with_externalities(|ext| ext.storage_root()).unwrap()
+		};
+
+		ext.execute_with(|| {
+			sp_externalities::with_externalities(|ext| {
+				ext.set_storage(b"dogglesworth".to_vec(), b"cat".to_vec())
+			}).unwrap()
+		});
+		let expected = ext.execute_with(storage_root);
+		let (proved, proof) = ext.execute_and_prove(storage_root);
+		assert_eq!(proved, expected);
+
+		let proof_check = crate::create_proof_check_backend::<BlakeTwo256>(root, proof).unwrap();
+		let (checked, _) = proof_check.storage_root(
+			ext.overlay.changes().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
+		);
+		assert_eq!(checked.encode(), expected);
+	}
+
+	#[test]
+	fn checkpoint_rollback_and_commit_work() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.ext().set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+
+		let first = ext.checkpoint();
+		ext.ext().set_storage(b"doe".to_vec(), b"deer".to_vec());
+		ext.checkpoint();
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		ext.commit().unwrap();
+		assert_eq!(ext.ext().storage(b"dog"), Some(b"puppy".to_vec()));
+
+		ext.rollback_to(first).unwrap();
+		assert_eq!(ext.ext().storage(b"doe"), Some(b"reindeer".to_vec()));
+		assert_eq!(ext.ext().storage(b"dog"), None);
+
+		assert!(ext.rollback_to(first).is_err());
+		assert!(ext.commit().is_err());
+
+		let second = ext.checkpoint();
+		ext.checkpoint();
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		ext.rollback_to(second).unwrap();
+		assert_eq!(ext.ext().storage(b"dog"), None);
+
+		// Depths are reused once a checkpoint is closed.
+		let third = ext.checkpoint();
+		ext.commit().unwrap();
+		let fourth = ext.checkpoint();
+		assert_eq!(third, fourth);
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		ext.rollback_to(third).unwrap();
+		assert_eq!(ext.ext().storage(b"dog"), None);
+	}
+
+	#[test]
+	fn rollback_keeps_offchain_and_inserted_values() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+
+		let checkpoint = ext.checkpoint();
+		ext.ext().set_offchain_storage(b"doe", Some(b"reindeer"));
+		ext.insert(b"dog".to_vec(), b"puppy".to_vec());
+		ext.rollback_to(checkpoint).unwrap();
+
+		assert_eq!(ext.ext().storage(b"dog"), Some(b"puppy".to_vec()));
+		ext.persist_offchain_overlay();
+		assert_eq!(
+			ext.offchain_db().get(b"", b"doe"),
+			Some(b"reindeer".to_vec()),
+		);
+	}
+
 	#[test]
 	fn check_send() {
 		fn assert_send<T: Send>() {}